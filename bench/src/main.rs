@@ -0,0 +1,178 @@
+// Copyright 2018 Bitwise IO
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Standalone benchmark driver for the block-application pipeline.
+//!
+//! It generates synthetic blocks with configurable numbers of participant and
+//! organization operations, applies them through
+//! `DataManager::execute_transactions_in_block` against a throwaway SQLite
+//! schema, and reports blocks/sec and rows/sec. A dedicated reorg scenario
+//! measures `drop_fork` cost at increasing depths so contributors get a
+//! regression signal when touching the transaction batching code.
+
+#[macro_use]
+extern crate clap;
+extern crate bond_database;
+
+use std::time::Instant;
+
+use bond_database::{
+    custom_types::*, models::*,
+    data_manager::{MAX_BLOCK_NUM, OperationType},
+    storage::{self, Storage},
+};
+
+/// A single benchmark scenario and the synthetic load it applies.
+struct Scenario {
+    name: &'static str,
+    blocks: usize,
+    participants_per_block: usize,
+    organizations_per_block: usize,
+}
+
+fn main() {
+    let matches = clap_app!(sawtooth_bond_bench =>
+        (version: crate_version!())
+        (about: "Sawtooth Bond block-application benchmarks")
+        (@arg blocks: --blocks default_value("500") +takes_value
+           "number of synthetic blocks to apply")
+        (@arg rows: --rows default_value("50") +takes_value
+           "number of rows of each kind inserted per block"))
+        .get_matches();
+
+    let blocks = value_t!(matches, "blocks", usize).unwrap_or(500);
+    let rows = value_t!(matches, "rows", usize).unwrap_or(50);
+
+    let scenarios = vec![
+        Scenario { name: "participants", blocks, participants_per_block: rows, organizations_per_block: 0 },
+        Scenario { name: "organizations", blocks, participants_per_block: 0, organizations_per_block: rows },
+        Scenario { name: "mixed", blocks, participants_per_block: rows, organizations_per_block: rows },
+    ];
+
+    for scenario in &scenarios {
+        run_apply_scenario(scenario);
+    }
+
+    for depth in &[1i64, 10, 100, 1_000] {
+        run_reorg_scenario(*depth);
+    }
+}
+
+/// Applies `scenario.blocks` synthetic blocks and reports throughput.
+fn run_apply_scenario(scenario: &Scenario) {
+    let storage = fresh_storage();
+    let rows_per_block = scenario.participants_per_block + scenario.organizations_per_block;
+
+    let start = Instant::now();
+    let mut previous_block_id: Option<String> = None;
+    for height in 1..=(scenario.blocks as i64) {
+        let block = make_block(height, previous_block_id.clone());
+        let transactions = make_operations(
+            height,
+            scenario.participants_per_block,
+            scenario.organizations_per_block,
+        );
+        storage
+            .execute_transactions_in_block(transactions, &block)
+            .expect("Error applying synthetic block");
+        previous_block_id = block.block_id.clone();
+    }
+    let elapsed = start.elapsed();
+
+    let seconds = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+    let total_rows = scenario.blocks * rows_per_block;
+    println!(
+        "apply[{}]: {} blocks, {} rows in {:.3}s => {:.0} blocks/sec, {:.0} rows/sec",
+        scenario.name,
+        scenario.blocks,
+        total_rows,
+        seconds,
+        scenario.blocks as f64 / seconds,
+        total_rows as f64 / seconds,
+    );
+}
+
+/// Builds a chain of `depth` blocks then measures the cost of dropping it.
+fn run_reorg_scenario(depth: i64) {
+    let storage = fresh_storage();
+    let mut previous_block_id: Option<String> = None;
+    for height in 1..=depth {
+        let block = make_block(height, previous_block_id.clone());
+        storage
+            .execute_transactions_in_block(make_operations(height, 1, 1), &block)
+            .expect("Error building chain");
+        previous_block_id = block.block_id.clone();
+    }
+
+    let start = Instant::now();
+    storage.drop_fork(1).expect("Error dropping fork");
+    let elapsed = start.elapsed();
+
+    let millis = f64::from(elapsed.subsec_nanos()) / 1e6 + elapsed.as_secs() as f64 * 1e3;
+    println!("reorg[depth={}]: drop_fork took {:.3}ms", depth, millis);
+}
+
+fn fresh_storage() -> Box<dyn Storage + Send> {
+    // An anonymous in-memory database is a throwaway schema that is discarded
+    // when the connection is dropped.
+    storage::open("sqlite://:memory:").expect("Error opening benchmark storage")
+}
+
+fn make_block(height: i64, previous_block_id: Option<String>) -> Block {
+    Block {
+        block_num: height,
+        block_id: Some(format!("block-{}", height)),
+        previous_block_id,
+    }
+}
+
+fn make_operations(
+    height: i64,
+    participants: usize,
+    organizations: usize,
+) -> Vec<OperationType> {
+    let mut operations = Vec::new();
+
+    if participants > 0 {
+        let new_participants = (0..participants)
+            .map(|i| NewParticipant {
+                public_key: format!("pk-{}-{}", height, i),
+                organization_id: format!("org-{}", i),
+                username: format!("user-{}", i),
+                start_block_num: height,
+                end_block_num: MAX_BLOCK_NUM,
+            })
+            .collect();
+        operations.push(OperationType::InsertParticipants(new_participants));
+    }
+
+    if organizations > 0 {
+        let new_organizations = (0..organizations)
+            .map(|i| {
+                let organization = NewOrganization {
+                    organization_id: format!("org-{}-{}", height, i),
+                    industry: Some(String::from("benchmark")),
+                    name: Some(format!("org-{}", i)),
+                    organization_type: OrganizationTypeEnum::TRADINGFIRM,
+                    start_block_num: height,
+                    end_block_num: MAX_BLOCK_NUM,
+                };
+                (organization, Vec::new())
+            })
+            .collect();
+        operations.push(OperationType::InsertOrganizations(new_organizations));
+    }
+
+    operations
+}
@@ -12,6 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::error::Error;
+use std::fmt;
+
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 
@@ -26,6 +29,29 @@ const ORDER: &str = "order";
 const QUOTE: &str = "quote";
 
 const PREFIX_SIZE: usize = 6;
+const ADDRESS_LENGTH: usize = 70;
+/// Prefix of the Sawtooth on-chain settings namespace (`sawtooth.settings`).
+const SETTINGS_NAMESPACE: &str = "000000";
+/// Offset where the object-specific segments begin (namespace + type infix).
+const BODY_OFFSET: usize = PREFIX_SIZE * 2;
+/// Width of the organization segment in organization-scoped addresses.
+const ORGANIZATION_SEGMENT: usize = 22;
+
+lazy_static! {
+    /// The eight type infixes, hashed once, paired with the space they select.
+    /// `get_address_type` and `decode_address` consult this instead of hashing
+    /// every object name on every call.
+    static ref TYPE_PREFIXES: Vec<(String, AddressSpace)> = vec![
+        (hash(ORGANIZATION, PREFIX_SIZE), AddressSpace::ORGANIZATION),
+        (hash(PARTICIPANT, PREFIX_SIZE), AddressSpace::PARTICIPANT),
+        (hash(SETTLEMENT, PREFIX_SIZE), AddressSpace::SETTLEMENT),
+        (hash(HOLDING, PREFIX_SIZE), AddressSpace::HOLDING),
+        (hash(RECEIPT, PREFIX_SIZE), AddressSpace::RECEIPT),
+        (hash(ORDER, PREFIX_SIZE), AddressSpace::ORDER),
+        (hash(QUOTE, PREFIX_SIZE), AddressSpace::QUOTE),
+        (hash(BOND, PREFIX_SIZE), AddressSpace::BOND),
+    ];
+}
 
 pub fn hash(object: &str, num: usize) -> String {
     let mut sha = Sha256::new();
@@ -37,6 +63,13 @@ pub fn get_bond_namespace() -> String {
     hash(&FAMILY_NAMESPACE, PREFIX_SIZE)
 }
 
+/// The Sawtooth on-chain settings namespace (`sawtooth.settings`). Governance
+/// parameters live here rather than under the bond family, so a subscriber that
+/// indexes settings must follow this prefix explicitly.
+pub fn get_settings_namespace() -> String {
+    SETTINGS_NAMESPACE.to_string()
+}
+
 pub fn make_organization_address(organization_id: &str) -> String {
     get_bond_namespace() + &hash(&ORGANIZATION, PREFIX_SIZE) + &hash(organization_id, 58)
 }
@@ -84,7 +117,7 @@ pub fn make_order_address(organization_id: &str, bond_id: &str) -> String {
         + &hash(bond_id, 36)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AddressSpace {
     ORGANIZATION,
     PARTICIPANT,
@@ -94,51 +127,208 @@ pub enum AddressSpace {
     ORDER,
     QUOTE,
     BOND,
+    SETTING,
     ANOTHER_FAMILY
 }
 
-/// that takes in an address from state, and
-/// returns the kind of state object that address
-/// represents
-
-pub fn get_address_type(address: &str) -> AddressSpace {
+impl AddressSpace {
+    /// Returns the object-name string this space hashes into the type infix of
+    /// an address, or `None` for addresses outside the bond family.
+    fn object_name(&self) -> Option<&'static str> {
+        match self {
+            AddressSpace::ORGANIZATION => Some(ORGANIZATION),
+            AddressSpace::PARTICIPANT => Some(PARTICIPANT),
+            AddressSpace::SETTLEMENT => Some(SETTLEMENT),
+            AddressSpace::HOLDING => Some(HOLDING),
+            AddressSpace::RECEIPT => Some(RECEIPT),
+            AddressSpace::ORDER => Some(ORDER),
+            AddressSpace::QUOTE => Some(QUOTE),
+            AddressSpace::BOND => Some(BOND),
+            AddressSpace::SETTING => None,
+            AddressSpace::ANOTHER_FAMILY => None,
+        }
+    }
+}
 
-    let infix = &address[PREFIX_SIZE..PREFIX_SIZE*2];
+/// A coarse-to-fine subscription filter expressed in domain terms.
+///
+/// A filter names the object type to follow and, optionally, the organization
+/// and object the consumer cares about. `match_prefix` lowers it to the
+/// concrete address prefix produced by the `make_*_address` builders, so a
+/// subscriber can ask the validator for only the deltas it will index.
+pub struct AddressFilter {
+    pub space: AddressSpace,
+    pub organization_id: Option<String>,
+    pub object_id: Option<String>,
+}
 
-    let organization_prefix = &hash(&ORGANIZATION, PREFIX_SIZE);
-    let partcipant_prefix = hash(&PARTICIPANT, PREFIX_SIZE);
-    let settlement_prefix = &hash(&SETTLEMENT, PREFIX_SIZE);
-    let holding_prefix = &hash(&HOLDING, PREFIX_SIZE);
-    let receipt_prefix = &hash(RECEIPT, PREFIX_SIZE);
-    let order_prefix = &hash(&ORDER, PREFIX_SIZE);
-    let quote_prefix = &hash(&QUOTE, PREFIX_SIZE);
-    let bond_prefix = &hash(&BOND, PREFIX_SIZE);
+impl AddressFilter {
+    pub fn new(space: AddressSpace) -> AddressFilter {
+        AddressFilter {
+            space,
+            organization_id: None,
+            object_id: None,
+        }
+    }
 
-    if infix == organization_prefix {
-        return AddressSpace::ORGANIZATION
+    pub fn for_organization(space: AddressSpace, organization_id: &str) -> AddressFilter {
+        AddressFilter {
+            space,
+            organization_id: Some(organization_id.to_string()),
+            object_id: None,
+        }
     }
-    else if infix == partcipant_prefix {
-        return AddressSpace::PARTICIPANT
+
+    pub fn for_object(space: AddressSpace, organization_id: &str, object_id: &str) -> AddressFilter {
+        AddressFilter {
+            space,
+            organization_id: Some(organization_id.to_string()),
+            object_id: Some(object_id.to_string()),
+        }
     }
-    else if infix == settlement_prefix {
-        return AddressSpace::SETTLEMENT
+
+    /// Builds the address prefix this filter matches. Organization-scoped
+    /// objects (holding/settlement/receipt/quote/order) hash the organization
+    /// into a 22-char segment and the object into a 36-char segment; the
+    /// top-level objects (organization/participant/bond) use a single 58-char
+    /// segment. Segments are only appended while the caller has supplied them,
+    /// so `space`-only filters match every object of a type.
+    pub fn match_prefix(&self) -> String {
+        // Settings live outside the bond family in their own namespace, so the
+        // prefix is that namespace rather than a bond type infix.
+        if self.space == AddressSpace::SETTING {
+            return get_settings_namespace();
+        }
+        let mut prefix = get_bond_namespace();
+        let object_name = match self.space.object_name() {
+            Some(name) => name,
+            None => return prefix,
+        };
+        prefix += &hash(object_name, PREFIX_SIZE);
+
+        match self.space {
+            AddressSpace::HOLDING
+            | AddressSpace::SETTLEMENT
+            | AddressSpace::RECEIPT
+            | AddressSpace::QUOTE
+            | AddressSpace::ORDER => {
+                if let Some(ref organization_id) = self.organization_id {
+                    prefix += &hash(organization_id, 22);
+                    if let Some(ref object_id) = self.object_id {
+                        prefix += &hash(object_id, 36);
+                    }
+                }
+            }
+            _ => {
+                if let Some(ref object_id) = self.object_id {
+                    prefix += &hash(object_id, 58);
+                }
+            }
+        }
+        prefix
     }
-    else if infix == holding_prefix {
-        return AddressSpace::HOLDING
+}
+
+/// that takes in an address from state, and
+/// returns the kind of state object that address
+/// represents
+
+pub fn get_address_type(address: &str) -> AddressSpace {
+    if address.starts_with(SETTINGS_NAMESPACE) {
+        return AddressSpace::SETTING;
     }
-    else if infix == receipt_prefix {
-        return AddressSpace::RECEIPT
+    if address.len() < BODY_OFFSET {
+        return AddressSpace::ANOTHER_FAMILY;
     }
-    else if infix == order_prefix {
-        return AddressSpace::ORDER
+    let infix = &address[PREFIX_SIZE..BODY_OFFSET];
+    TYPE_PREFIXES
+        .iter()
+        .find(|(prefix, _)| prefix == infix)
+        .map(|(_, space)| *space)
+        .unwrap_or(AddressSpace::ANOTHER_FAMILY)
+}
+
+/// Why an address could not be decoded.
+#[derive(Debug)]
+pub enum AddressError {
+    /// The address was not exactly 70 hex characters.
+    InvalidLength(usize),
+    /// The address contained non-hex characters.
+    InvalidContent(String),
+    /// The type infix did not match any bond object.
+    UnknownAddressType(String),
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AddressError::InvalidLength(len) => {
+                write!(f, "address must be {} characters, got {}", ADDRESS_LENGTH, len)
+            }
+            AddressError::InvalidContent(address) => {
+                write!(f, "address is not hexadecimal: {}", address)
+            }
+            AddressError::UnknownAddressType(address) => {
+                write!(f, "address does not belong to a known bond object: {}", address)
+            }
+        }
     }
-    else if infix == quote_prefix {
-        return AddressSpace::QUOTE
+}
+
+impl Error for AddressError {}
+
+/// The structured result of decoding a bond address: its object type and the
+/// sub-fields encoded after the namespace and type infix.
+#[derive(Debug)]
+pub struct DecodedAddress {
+    pub space: AddressSpace,
+    /// The 22-char organization segment of an organization-scoped address
+    /// (holding/settlement/receipt/quote/order).
+    pub organization_segment: Option<String>,
+    /// The trailing object segment: 36 chars for organization-scoped objects,
+    /// 58 chars for the top-level objects (organization/participant/bond).
+    pub object_segment: Option<String>,
+}
+
+/// Validates `address` and decodes its object type and sub-fields. This is the
+/// reverse of the `make_*_address` builders and the routing entry point the
+/// event handler uses instead of matching raw prefixes.
+pub fn decode_address(address: &str) -> Result<DecodedAddress, AddressError> {
+    if address.len() != ADDRESS_LENGTH {
+        return Err(AddressError::InvalidLength(address.len()));
     }
-    else if infix == bond_prefix {
-        return AddressSpace::BOND
+    if !address.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(AddressError::InvalidContent(address.to_string()));
     }
-    else {
-        return AddressSpace::ANOTHER_FAMILY
+
+    let space = get_address_type(address);
+    let body = &address[BODY_OFFSET..];
+    match space {
+        AddressSpace::HOLDING
+        | AddressSpace::SETTLEMENT
+        | AddressSpace::RECEIPT
+        | AddressSpace::QUOTE
+        | AddressSpace::ORDER => Ok(DecodedAddress {
+            space,
+            organization_segment: Some(body[..ORGANIZATION_SEGMENT].to_string()),
+            object_segment: Some(body[ORGANIZATION_SEGMENT..].to_string()),
+        }),
+        AddressSpace::ORGANIZATION | AddressSpace::PARTICIPANT | AddressSpace::BOND => {
+            Ok(DecodedAddress {
+                space,
+                organization_segment: None,
+                object_segment: Some(body.to_string()),
+            })
+        }
+        AddressSpace::SETTING => Ok(DecodedAddress {
+            space,
+            organization_segment: None,
+            // The settings key is hashed into the address after the namespace
+            // prefix; keep the whole remainder as the object segment.
+            object_segment: Some(address[PREFIX_SIZE..].to_string()),
+        }),
+        AddressSpace::ANOTHER_FAMILY => {
+            Err(AddressError::UnknownAddressType(address.to_string()))
+        }
     }
 }
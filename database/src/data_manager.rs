@@ -18,10 +18,16 @@ use diesel::prelude::*;
 use errors::DatabaseError;
 use models::*;
 use std::i64;
+use storage::Storage;
 use tables_schema::*;
 
 pub const MAX_BLOCK_NUM: i64 = i64::MAX;
 
+/// How many recent block ids to hand the validator as subscription resume
+/// points. A short chain of ids lets the validator pick the newest one that is
+/// still canonical even if our very latest block was orphaned by a reorg.
+pub(crate) const RESUME_BLOCK_DEPTH: i64 = 16;
+
 pub struct DataManager {
     conn: DieselConnection,
 }
@@ -29,6 +35,7 @@ pub struct DataManager {
 pub enum OperationType {
     InsertParticipants(Vec<NewParticipant>),
     InsertOrganizations(Vec<(NewOrganization, Vec<NewAuthorization>)>),
+    UpsertSettings(Vec<NewSetting>),
 }
 
 impl DataManager {
@@ -49,15 +56,41 @@ impl DataManager {
     ) -> Result<(), DatabaseError> {
         let conn = &*self.conn;
         conn.transaction::<_, _, _>(|| {
-            let block_in_db = self.get_block_if_exists(block.block_num)?;
-            if block_in_db.is_some() {
-                if self.is_fork(&block_in_db.unwrap(), block) {
+            if let Some(block_in_db) = self.get_block_if_exists(block.block_num)? {
+                if !self.is_fork(&block_in_db, block) {
+                    return Ok(()); // same block id at this height: a duplicate, nothing to do
+                }
+                if block.previous_block_id.is_none() {
+                    // Without a parent link the block cannot be placed by
+                    // ancestry, so the competing block at this height and
+                    // everything above it are dropped as a fork.
                     self.drop_fork(block.block_num)?;
                 }
-                else {
-                    return Ok(()) // if block already exists in db and is not a fork, it is a duplicate, and nothing needs to be done
+            }
+
+            // Decide how the incoming block relates to the canonical tip. If it
+            // extends the tip we simply append; otherwise it belongs to a
+            // competing branch and we may need to reorg back to the common
+            // ancestor before replaying it. A block without a parent link is
+            // appended as it is.
+            if let Some(tip) = self.get_best_tip()? {
+                let extends_tip = block.previous_block_id.as_ref() == tip.block_id.as_ref()
+                    && block.previous_block_id.is_some();
+                if !extends_tip && block.previous_block_id.is_some() {
+                    // The block belongs to a competing branch. The validator only
+                    // streams blocks for the chain it has made canonical, oldest
+                    // first, so the first such block rejoins our chain at its
+                    // parent; we roll back to that common ancestor and append this
+                    // block, and every following block on the branch then simply
+                    // extends the new tip. A block whose parent we do not yet hold
+                    // is out of order and is left for a later block to connect.
+                    match self.find_common_ancestor(block)? {
+                        Some(common_ancestor) => self.reorg_to(common_ancestor)?,
+                        None => return Ok(()),
+                    }
                 }
             }
+
             for transaction in transactions {
                 self.execute_transaction(transaction)?;
             }
@@ -66,6 +99,75 @@ impl DataManager {
         })
     }
 
+    /// Returns the canonical tip: the highest block currently in the table.
+    /// Because orphaned blocks are deleted on reorg, every stored block is on
+    /// the canonical chain, so the maximum `block_num` is the tip.
+    fn get_best_tip(&self) -> Result<Option<Block>, DatabaseError> {
+        let mut blocks = blocks::table
+            .order(blocks::block_num.desc())
+            .limit(1)
+            .load::<Block>(&*self.conn)?;
+        if blocks.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(blocks.remove(0)))
+    }
+
+    fn get_block_by_id(&self, block_id: &str) -> Result<Option<Block>, DatabaseError> {
+        let mut blocks = blocks::table
+            .filter(blocks::block_id.eq(block_id))
+            .load::<Block>(&*self.conn)?;
+        if blocks.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(blocks.remove(0)))
+    }
+
+    /// Returns the height of the block the incoming branch rejoins the canonical
+    /// chain at, or `None` if its parent is not a block we currently hold. Every
+    /// stored block is canonical, so the incoming block's parent, if present in
+    /// the table, is itself the common ancestor. `None` means the block arrived
+    /// before the rest of its branch and cannot be connected yet.
+    fn find_common_ancestor(&self, block: &Block) -> Result<Option<i64>, DatabaseError> {
+        if let Some(ref previous_block_id) = block.previous_block_id {
+            if let Some(ancestor) = self.get_block_by_id(previous_block_id)? {
+                return Ok(Some(ancestor.block_num));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Rolls the canonical chain back so its tip is height `common_ancestor`, discarding
+    /// everything strictly above it. This generalizes `drop_fork` to reorgs of
+    /// arbitrary depth and is expressed in terms of the temporal rollback.
+    fn reorg_to(&self, common_ancestor: i64) -> Result<(), DatabaseError> {
+        self.rollback_projection(common_ancestor)?;
+        diesel::delete(blocks::table.filter(blocks::block_num.gt(common_ancestor)))
+            .execute(&*self.conn)?;
+        Ok(())
+    }
+
+    /// Rolls the relational projection back to block `n`, atomically. Because the
+    /// schema is temporal the undo is two SQL passes: delete records that were
+    /// *created* in the orphaned blocks (`start_block_num > n`), and reopen
+    /// records that were *closed* by an orphaned delete/update
+    /// (`end_block_num > n`). Callers invoke this before replaying a new branch.
+    /// The rollback commits in its own transaction; the replay that follows is
+    /// a separate transaction, so undo and redo are not applied as one unit.
+    pub fn rollback_to_block(&self, n: i64) -> Result<(), DatabaseError> {
+        let conn = &*self.conn;
+        conn.transaction::<_, DatabaseError, _>(|| self.reorg_to(n))
+    }
+
+    fn rollback_projection(&self, n: i64) -> Result<(), DatabaseError> {
+        diesel::delete(chain_record::table.filter(chain_record::start_block_num.gt(n)))
+            .execute(&*self.conn)?;
+        diesel::update(chain_record::table.filter(chain_record::end_block_num.gt(n)))
+            .set(chain_record::end_block_num.eq(MAX_BLOCK_NUM))
+            .execute(&*self.conn)?;
+        Ok(())
+    }
+
     fn execute_transaction(&self, transaction: OperationType) -> Result<(), DatabaseError> {
         match transaction {
             OperationType::InsertParticipants(participants) => {
@@ -78,7 +180,67 @@ impl DataManager {
                 }
                 Ok(())
             }
+            OperationType::UpsertSettings(settings) => self.insert_setting(&settings),
+        }
+    }
+
+    fn insert_setting(&self, settings: &[NewSetting]) -> Result<(), DatabaseError> {
+        for setting in settings {
+            let modified_setting_query = on_chain_settings::table
+                .filter(on_chain_settings::end_block_num.eq(MAX_BLOCK_NUM))
+                .filter(on_chain_settings::key.eq(&setting.key));
+
+            diesel::update(modified_setting_query)
+                .set(on_chain_settings::end_block_num.eq(setting.start_block_num))
+                .execute(&*self.conn)?;
         }
+        diesel::insert_into(on_chain_settings::table)
+            .values(settings)
+            .execute(&*self.conn)?;
+        Ok(())
+    }
+
+    /// Appends a received `EventList` to the authoritative log. The raw
+    /// protobuf bytes are the source of truth; the projection tables are derived
+    /// from them and can be rebuilt by replaying the log.
+    pub fn insert_block_event(&self, block_event: &NewBlockEvent) -> Result<(), DatabaseError> {
+        diesel::insert_into(block_events::table)
+            .values(block_event)
+            .execute(&*self.conn)?;
+        Ok(())
+    }
+
+    /// Returns every logged `EventList` in ascending block order, ready to be
+    /// replayed to regenerate the projection.
+    pub fn get_block_events(&self) -> Result<Vec<BlockEvent>, DatabaseError> {
+        Ok(block_events::table
+            .order(block_events::block_num.asc())
+            .load::<BlockEvent>(&*self.conn)?)
+    }
+
+    /// Truncates the derived projection (but not the authoritative log) so it
+    /// can be regenerated from scratch by a replay.
+    pub fn truncate_projection(&self) -> Result<(), DatabaseError> {
+        let conn = &*self.conn;
+        conn.transaction::<_, DatabaseError, _>(|| {
+            diesel::delete(chain_record::table).execute(conn)?;
+            diesel::delete(blocks::table).execute(conn)?;
+            Ok(())
+        })
+    }
+
+    /// Returns the most recently committed block ids, newest first, to be used
+    /// as resume points for a delta subscription. The validator replays only
+    /// the blocks that follow the first id it still recognizes.
+    pub fn get_last_known_blocks(&self) -> Result<Vec<String>, DatabaseError> {
+        let blocks = blocks::table
+            .order(blocks::block_num.desc())
+            .limit(RESUME_BLOCK_DEPTH)
+            .load::<Block>(&*self.conn)?;
+        Ok(blocks
+            .into_iter()
+            .filter_map(|block| block.block_id)
+            .collect())
     }
 
     fn insert_block(&self, block: &Block) -> Result<(), DatabaseError> {
@@ -198,7 +360,7 @@ impl DataManager {
         Ok(())
     }
 
-    fn drop_fork(&self, block_num: i64) -> Result<(), DatabaseError> {
+    pub fn drop_fork(&self, block_num: i64) -> Result<(), DatabaseError> {
         let to_drop_query = chain_record::table.filter(chain_record::start_block_num.ge(block_num));
 
         diesel::delete(to_drop_query).execute(&*self.conn)?;
@@ -217,6 +379,44 @@ impl DataManager {
     }
 }
 
+impl Storage for DataManager {
+    fn execute_transactions_in_block(
+        &self,
+        transactions: Vec<OperationType>,
+        block: &Block,
+    ) -> Result<(), DatabaseError> {
+        DataManager::execute_transactions_in_block(self, transactions, block)
+    }
+
+    fn get_last_known_blocks(&self) -> Result<Vec<String>, DatabaseError> {
+        DataManager::get_last_known_blocks(self)
+    }
+
+    fn drop_fork(&self, block_num: i64) -> Result<(), DatabaseError> {
+        DataManager::drop_fork(self, block_num)
+    }
+
+    fn insert_block_event(&self, block_event: &NewBlockEvent) -> Result<(), DatabaseError> {
+        DataManager::insert_block_event(self, block_event)
+    }
+
+    fn get_block_events(&self) -> Result<Vec<BlockEvent>, DatabaseError> {
+        DataManager::get_block_events(self)
+    }
+
+    fn truncate_projection(&self) -> Result<(), DatabaseError> {
+        DataManager::truncate_projection(self)
+    }
+
+    fn rollback_to_block(&self, n: i64) -> Result<(), DatabaseError> {
+        DataManager::rollback_to_block(self, n)
+    }
+
+    fn find_block_num(&self, block_id: &str) -> Result<Option<i64>, DatabaseError> {
+        Ok(self.get_block_by_id(block_id)?.map(|block| block.block_num))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::super::{models::*, tables_schema::*};
@@ -249,6 +449,7 @@ mod tests {
         Block {
             block_num: block_num,
             block_id: Some(String::from(block_id)),
+            previous_block_id: None,
         }
     }
 
@@ -0,0 +1,250 @@
+// Copyright 2018 Bitwise IO
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use diesel;
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use data_manager::{OperationType, MAX_BLOCK_NUM, RESUME_BLOCK_DEPTH};
+use errors::DatabaseError;
+use models::*;
+use storage::Storage;
+use tables_schema::*;
+
+/// Applies the temporal rollback to one domain table: delete the rows that were
+/// *created* above block `n` and reopen the rows that were *closed* above it.
+/// The Postgres schema lets a single `chain_record` statement cascade to every
+/// object table through inheritance; SQLite has no such inheritance, so each
+/// table is handled explicitly.
+macro_rules! rollback_domain_table {
+    ($conn:expr, $table:ident, $n:expr) => {{
+        diesel::delete($table::table.filter($table::start_block_num.gt($n)))
+            .execute($conn)?;
+        diesel::update($table::table.filter($table::end_block_num.gt($n)))
+            .set($table::end_block_num.eq(MAX_BLOCK_NUM))
+            .execute($conn)?;
+    }};
+}
+
+/// Deletes every row of one domain table, used when the whole projection is
+/// being regenerated from the event log.
+macro_rules! truncate_domain_table {
+    ($conn:expr, $table:ident) => {{
+        diesel::delete($table::table).execute($conn)?;
+    }};
+}
+
+/// An embedded, SQLite-backed `Storage` implementation for single-node and test
+/// deployments. It mirrors the block-range semantics of the Postgres
+/// `DataManager`. Forks are handled only at the same height: a block whose id
+/// differs from the stored block at its height drops that height and everything
+/// above it. Parent links are not followed in `execute_transactions_in_block`.
+pub struct SqliteStorage {
+    conn: SqliteConnection,
+}
+
+impl SqliteStorage {
+    pub fn new(dsn: &str) -> Result<SqliteStorage, DatabaseError> {
+        let path = dsn.trim_start_matches("sqlite://");
+        let conn = SqliteConnection::establish(path)?;
+        // Ensure the projection schema exists so a fresh (in-memory) database is
+        // immediately writable; the statements are idempotent for a persistent
+        // one.
+        conn.batch_execute(include_str!("../tables/sqlite_schema.sql"))?;
+        Ok(SqliteStorage { conn })
+    }
+
+    fn get_block_if_exists(&self, block_num: i64) -> Result<Option<Block>, DatabaseError> {
+        let mut blocks = blocks::table.find(block_num).load::<Block>(&self.conn)?;
+        if blocks.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(blocks.remove(0)))
+    }
+
+    fn is_fork(&self, block_in_db: &Block, block_to_be_inserted: &Block) -> bool {
+        block_in_db.block_id != block_to_be_inserted.block_id
+    }
+
+    fn insert_block(&self, block: &Block) -> Result<(), DatabaseError> {
+        diesel::insert_into(blocks::table)
+            .values(block)
+            .execute(&self.conn)?;
+        Ok(())
+    }
+
+    fn rollback_projection(&self, n: i64) -> Result<(), DatabaseError> {
+        rollback_domain_table!(&self.conn, participants, n);
+        rollback_domain_table!(&self.conn, organizations, n);
+        rollback_domain_table!(&self.conn, authorizations, n);
+        rollback_domain_table!(&self.conn, on_chain_settings, n);
+        Ok(())
+    }
+
+    fn execute_transaction(&self, transaction: OperationType) -> Result<(), DatabaseError> {
+        match transaction {
+            OperationType::InsertParticipants(participants) => {
+                self.insert_participant(&participants)
+            }
+            OperationType::InsertOrganizations(organizations) => {
+                for (organization, authorizations) in organizations {
+                    self.insert_organization(&organization)?;
+                    self.insert_authorization(&authorizations)?;
+                }
+                Ok(())
+            }
+            OperationType::UpsertSettings(settings) => self.insert_setting(&settings),
+        }
+    }
+
+    fn insert_setting(&self, settings: &[NewSetting]) -> Result<(), DatabaseError> {
+        for setting in settings {
+            let modified = on_chain_settings::table
+                .filter(on_chain_settings::end_block_num.eq(MAX_BLOCK_NUM))
+                .filter(on_chain_settings::key.eq(&setting.key));
+            diesel::update(modified)
+                .set(on_chain_settings::end_block_num.eq(setting.start_block_num))
+                .execute(&self.conn)?;
+        }
+        diesel::insert_into(on_chain_settings::table)
+            .values(settings)
+            .execute(&self.conn)?;
+        Ok(())
+    }
+
+    fn insert_participant(&self, participants: &[NewParticipant]) -> Result<(), DatabaseError> {
+        for participant in participants {
+            let modified = participants::table
+                .filter(participants::end_block_num.eq(MAX_BLOCK_NUM))
+                .filter(participants::public_key.eq(&participant.public_key));
+            diesel::update(modified)
+                .set(participants::end_block_num.eq(participant.start_block_num))
+                .execute(&self.conn)?;
+        }
+        diesel::insert_into(participants::table)
+            .values(participants)
+            .execute(&self.conn)?;
+        Ok(())
+    }
+
+    fn insert_organization(&self, organization: &NewOrganization) -> Result<(), DatabaseError> {
+        let modified = organizations::table
+            .filter(organizations::end_block_num.eq(MAX_BLOCK_NUM))
+            .filter(organizations::organization_id.eq(&organization.organization_id));
+        diesel::update(modified)
+            .set(organizations::end_block_num.eq(organization.start_block_num))
+            .execute(&self.conn)?;
+        diesel::insert_into(organizations::table)
+            .values(organization)
+            .execute(&self.conn)?;
+        Ok(())
+    }
+
+    fn insert_authorization(&self, authorizations: &[NewAuthorization]) -> Result<(), DatabaseError> {
+        for authorization in authorizations {
+            let modified = authorizations::table
+                .filter(authorizations::organization_id.eq(&authorization.organization_id))
+                .filter(authorizations::participant_public_key.eq(&authorization.participant_public_key))
+                .filter(authorizations::end_block_num.eq(MAX_BLOCK_NUM));
+            diesel::update(modified)
+                .set(authorizations::end_block_num.eq(authorization.start_block_num))
+                .execute(&self.conn)?;
+        }
+        diesel::insert_into(authorizations::table)
+            .values(authorizations)
+            .execute(&self.conn)?;
+        Ok(())
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn execute_transactions_in_block(
+        &self,
+        transactions: Vec<OperationType>,
+        block: &Block,
+    ) -> Result<(), DatabaseError> {
+        self.conn.transaction::<_, DatabaseError, _>(|| {
+            if let Some(block_in_db) = self.get_block_if_exists(block.block_num)? {
+                if self.is_fork(&block_in_db, block) {
+                    Storage::drop_fork(self, block.block_num)?;
+                } else {
+                    return Ok(());
+                }
+            }
+            for transaction in transactions {
+                self.execute_transaction(transaction)?;
+            }
+            self.insert_block(block)?;
+            Ok(())
+        })
+    }
+
+    fn get_last_known_blocks(&self) -> Result<Vec<String>, DatabaseError> {
+        let blocks = blocks::table
+            .order(blocks::block_num.desc())
+            .limit(RESUME_BLOCK_DEPTH)
+            .load::<Block>(&self.conn)?;
+        Ok(blocks.into_iter().filter_map(|block| block.block_id).collect())
+    }
+
+    fn drop_fork(&self, block_num: i64) -> Result<(), DatabaseError> {
+        // Dropping the fork at `block_num` is the temporal rollback to the block
+        // just below it, followed by discarding the orphaned block rows.
+        self.rollback_projection(block_num - 1)?;
+        diesel::delete(blocks::table.filter(blocks::block_num.ge(block_num)))
+            .execute(&self.conn)?;
+        Ok(())
+    }
+
+    fn insert_block_event(&self, block_event: &NewBlockEvent) -> Result<(), DatabaseError> {
+        diesel::insert_into(block_events::table)
+            .values(block_event)
+            .execute(&self.conn)?;
+        Ok(())
+    }
+
+    fn get_block_events(&self) -> Result<Vec<BlockEvent>, DatabaseError> {
+        Ok(block_events::table
+            .order(block_events::block_num.asc())
+            .load::<BlockEvent>(&self.conn)?)
+    }
+
+    fn truncate_projection(&self) -> Result<(), DatabaseError> {
+        self.conn.transaction::<_, DatabaseError, _>(|| {
+            truncate_domain_table!(&self.conn, participants);
+            truncate_domain_table!(&self.conn, organizations);
+            truncate_domain_table!(&self.conn, authorizations);
+            truncate_domain_table!(&self.conn, on_chain_settings);
+            diesel::delete(blocks::table).execute(&self.conn)?;
+            Ok(())
+        })
+    }
+
+    fn rollback_to_block(&self, n: i64) -> Result<(), DatabaseError> {
+        self.conn.transaction::<_, DatabaseError, _>(|| {
+            self.rollback_projection(n)?;
+            diesel::delete(blocks::table.filter(blocks::block_num.gt(n)))
+                .execute(&self.conn)?;
+            Ok(())
+        })
+    }
+
+    fn find_block_num(&self, block_id: &str) -> Result<Option<i64>, DatabaseError> {
+        let mut blocks = blocks::table
+            .filter(blocks::block_id.eq(block_id))
+            .load::<Block>(&self.conn)?;
+        Ok(blocks.pop().map(|block| block.block_num))
+    }
+}
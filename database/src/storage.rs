@@ -0,0 +1,80 @@
+// Copyright 2018 Bitwise IO
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use data_manager::{DataManager, OperationType};
+use errors::DatabaseError;
+use models::{Block, BlockEvent, NewBlockEvent};
+use sqlite_storage::SqliteStorage;
+
+/// The backend-agnostic surface the indexer needs from a store.
+///
+/// Each implementation carries the block-range and fork semantics itself: it
+/// applies the `OperationType`s for a block atomically, closing the block range
+/// of the records they replace, reports which blocks it already holds, and
+/// rolls a fork back. This lets the same ingest
+/// code run against Postgres in production or SQLite for single-node and test
+/// deployments.
+///
+/// The trait is `Send` so the subscriber can hand ownership of the store to its
+/// dedicated DB worker thread; both backends wrap a single owned connection and
+/// are only ever used from one thread at a time.
+pub trait Storage: Send {
+    /// Applies every operation in `block` in a single transaction, handling
+    /// duplicate blocks and forks at the current height.
+    fn execute_transactions_in_block(
+        &self,
+        transactions: Vec<OperationType>,
+        block: &Block,
+    ) -> Result<(), DatabaseError>;
+
+    /// Returns the most recently committed block ids, newest first, for use as
+    /// subscription resume points.
+    fn get_last_known_blocks(&self) -> Result<Vec<String>, DatabaseError>;
+
+    /// Rolls the projection back to below `block_num`, discarding records that
+    /// only exist on the orphaned branch.
+    fn drop_fork(&self, block_num: i64) -> Result<(), DatabaseError>;
+
+    /// Appends a received `EventList` to the authoritative log. The projection
+    /// is derived from this log and can be regenerated by replaying it.
+    fn insert_block_event(&self, block_event: &NewBlockEvent) -> Result<(), DatabaseError>;
+
+    /// Returns every logged `EventList` in ascending block order, ready to be
+    /// replayed to regenerate the projection.
+    fn get_block_events(&self) -> Result<Vec<BlockEvent>, DatabaseError>;
+
+    /// Truncates the derived projection (but not the authoritative log) so it
+    /// can be regenerated from scratch by a replay.
+    fn truncate_projection(&self) -> Result<(), DatabaseError>;
+
+    /// Rolls the relational projection back to block `n`, atomically, undoing
+    /// every record created or closed in the blocks above it.
+    fn rollback_to_block(&self, n: i64) -> Result<(), DatabaseError>;
+
+    /// Returns the height of the stored block with this id, or `None` if we do
+    /// not hold it. Used to reconcile a fork against the real common ancestor
+    /// rather than guessing its depth.
+    fn find_block_num(&self, block_id: &str) -> Result<Option<i64>, DatabaseError>;
+}
+
+/// Opens the store named by `dsn`, selecting the backend from its scheme:
+/// `postgres://` uses the Diesel/Postgres manager, `sqlite://` the embedded
+/// SQLite manager.
+pub fn open(dsn: &str) -> Result<Box<dyn Storage + Send>, DatabaseError> {
+    if dsn.starts_with("sqlite://") {
+        Ok(Box::new(SqliteStorage::new(dsn)?))
+    } else {
+        Ok(Box::new(DataManager::new(dsn)?))
+    }
+}
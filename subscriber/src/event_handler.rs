@@ -14,28 +14,81 @@
 
 use bond_database::{
     custom_types::*, connection_pool::ConnectionPool, models::*,
-    data_manager::{DataManager, TransactionType, MAX_BLOCK_NUM}
+    data_manager::{TransactionType, MAX_BLOCK_NUM},
+    storage::Storage,
 };
 use sawtooth_sdk::messages::events::{EventList, Event, Event_Attribute};
 use sawtooth_sdk::messages::transaction_receipt::{StateChangeList, StateChange, StateChange_Type};
 use sawtooth_sdk::messages::setting::{Setting};
 use protobuf;
 use regex::Regex;
-use bond_common::addressing::{ AddressSpace, get_address_type};
+use bond_common::addressing::{ self, AddressSpace, decode_address};
 use bond_common::proto::{organization, participant, settlement, order, bond, holding, receipt, quote};
 
 
 pub struct EventHandler{
-    data_manager: DataManager,
+    store: Box<dyn Storage + Send>,
+    last_block_id: Option<String>,
 }
 
 impl EventHandler {
-    pub fn new(data_manager: DataManager) -> EventHandler {
-        EventHandler { data_manager }
+    pub fn new(store: Box<dyn Storage + Send>) -> EventHandler {
+        EventHandler { store, last_block_id: None }
     }
 
-    pub fn parse_events(&self, events_data: &[u8]) -> Result<(), String> {
+    /// Returns the block ids the subscriber should present to the validator as
+    /// resume points, most recent first. With nothing committed yet it returns
+    /// the null block id that precedes genesis, which asks the validator to
+    /// replay from genesis.
+    pub fn get_last_known_blocks(&self) -> Result<Vec<String>, String> {
+        let mut block_ids = self
+            .store
+            .get_last_known_blocks()
+            .map_err(|err| err.to_string())?;
+        if block_ids.is_empty() {
+            block_ids.push(String::from("0000000000000000"));
+        }
+        Ok(block_ids)
+    }
+
+    /// Ingests a received `EventList`: appends it to the authoritative log and
+    /// then applies it to the projection.
+    pub fn parse_events(&mut self, events_data: &[u8]) -> Result<(), String> {
         let event_list: EventList = self.unpack_data(events_data)?;
+        let block = self.parse_block(&event_list.get_events().to_vec())?;
+
+        let block_event = NewBlockEvent {
+            block_num: block.block_num,
+            block_id: block.block_id.clone(),
+            event_data: events_data.to_vec(),
+        };
+        self.store
+            .insert_block_event(&block_event)
+            .map_err(|err| err.to_string())?;
+
+        self.apply_events(&event_list)
+    }
+
+    /// Truncates the projection and replays the logged `EventList`s in block
+    /// order to regenerate it. Used by the `rebuild` subcommand after a decoder
+    /// fix or schema migration, so the projection can be recomputed rather than
+    /// re-synced from the validator.
+    pub fn rebuild(&mut self) -> Result<(), String> {
+        self.store
+            .truncate_projection()
+            .map_err(|err| err.to_string())?;
+        self.last_block_id = None;
+        let block_events = self.store.get_block_events().map_err(|err| err.to_string())?;
+        for block_event in block_events {
+            let event_list: EventList = self.unpack_data(&block_event.event_data)?;
+            self.apply_events(&event_list)?;
+        }
+        Ok(())
+    }
+
+    /// Applies a parsed `EventList` to the projection without touching the
+    /// authoritative log. Shared by live ingest and rebuild.
+    fn apply_events(&mut self, event_list: &EventList) -> Result<(), String> {
         let events = event_list.get_events().to_vec();
         let block = self.parse_block(&events)?;
         let state_changes = self.parse_state_delta_events(&events)?;
@@ -43,7 +96,65 @@ impl EventHandler {
         for transaction in state_changes {
             transactions.push(self.parse_transaction(transaction, &block)?);
         }
-        self.data_manager.execute_transactions_in_block(transactions, &block)?;
+
+        // On the first block after a restart, seed the last-processed id from
+        // the persisted chain so a fork that happened while the indexer was down
+        // is detected instead of silently applied on top of stale state.
+        if self.last_block_id.is_none() {
+            self.last_block_id = self
+                .store
+                .get_last_known_blocks()
+                .map_err(|err| err.to_string())?
+                .into_iter()
+                .next();
+        }
+
+        // If the incoming block does not chain onto the block we last processed
+        // the validator has switched to a competing branch. Roll the projection
+        // back to the real common ancestor — the stored block this branch forks
+        // from — before replaying the new block, so everything above the fork is
+        // undone and the winning branch is re-applied on a clean base. The fork
+        // can be arbitrarily deep, so the ancestor is looked up by the block's
+        // parent id rather than assumed to be one block back.
+        if let Some(ref last_block_id) = self.last_block_id {
+            if block.previous_block_id.as_ref() != Some(last_block_id) {
+                let common_ancestor = match block.previous_block_id {
+                    Some(ref previous_block_id) => self
+                        .store
+                        .find_block_num(previous_block_id)
+                        .map_err(|err| err.to_string())?,
+                    None => None,
+                };
+                match common_ancestor {
+                    Some(ancestor) => {
+                        warn!(
+                            "block {} does not chain onto {}; rolling projection back to block {}",
+                            block.block_num, last_block_id, ancestor
+                        );
+                        self.store
+                            .rollback_to_block(ancestor)
+                            .map_err(|err| err.to_string())?;
+                    }
+                    None => {
+                        // The branch forks below every block we still hold — a
+                        // reorg deeper than our retained history, e.g. after a
+                        // restart where the validator resubscribed us from the
+                        // chain head. We cannot reconcile incrementally and must
+                        // not apply this block onto a stale projection, so fail
+                        // loudly instead of silently dropping it (and every block
+                        // after it). A `rebuild` replays the event log from the
+                        // start to recover.
+                        return Err(format!(
+                            "cannot reconcile block {}: it forks below the retained chain; run a rebuild",
+                            block.block_num
+                        ));
+                    }
+                }
+            }
+        }
+
+        self.store.execute_transactions_in_block(transactions, &block)?;
+        self.last_block_id = block.block_id.clone();
 
         Ok(())
     }
@@ -53,10 +164,12 @@ impl EventHandler {
         let block_commit_event: Vec<&Event> = events.into_iter().filter(|e| e.get_event_type() == "sawtooth/block-commit").collect();
         let block_num: Vec<Event_Attribute> = block_commit_event[0].get_attributes().to_vec().into_iter().filter(|a| a.get_key() == "block_num").collect();
         let block_id: Vec<Event_Attribute> = block_commit_event[0].get_attributes().to_vec().into_iter().filter(|a| a.get_key() == "block_id").collect();
+        let previous_block_id: Vec<Event_Attribute> = block_commit_event[0].get_attributes().to_vec().into_iter().filter(|a| a.get_key() == "previous_block_id").collect();
 
         let b = Block {
             block_num: block_num[0].get_value().parse::<i64>().map_err(|err| err.to_string())?,
-            block_id: Some(block_id[0].get_value().to_string())
+            block_id: Some(block_id[0].get_value().to_string()),
+            previous_block_id: previous_block_id.first().map(|a| a.get_value().to_string())
         };
         Ok(b)
 
@@ -87,12 +200,16 @@ impl EventHandler {
         }
 
     fn get_namespace_regex(&self) -> Regex {
-        Regex::new(r"^000000").unwrap()
+        // Match both the bond family namespace and the Sawtooth settings
+        // namespace (000000), so governance parameters stored on-chain are
+        // indexed alongside the bond objects.
+        let pattern = format!("^(000000|{})", addressing::get_bond_namespace());
+        Regex::new(&pattern).unwrap()
     }
 
     fn parse_transaction(&self, state: StateChange, block: &Block) ->  Result<TransactionType, String> {
-        let address_type = get_address_type(state.get_address());
-        match address_type {
+        let decoded = decode_address(state.get_address()).map_err(|err| err.to_string())?;
+        match decoded.space {
             AddressSpace::ORGANIZATION => {
                 let org: organization::Organization = self.unpack_data(state.get_value())?;
                 let new_org = self.get_new_organization(&org, &block);
@@ -153,7 +270,30 @@ impl EventHandler {
                 let transaction = TransactionType::InsertQuote(new_quote);
                 Ok(transaction)
             }
+            AddressSpace::SETTING => {
+                let setting: Setting = self.unpack_data(state.get_value())?;
+                let new_settings = self.get_new_setting(&setting, &block);
+                let transaction = TransactionType::UpsertSetting(new_settings);
+                Ok(transaction)
+            }
+            AddressSpace::ANOTHER_FAMILY => {
+                Err(format!("received state delta for an unknown address: {}", state.get_address()))
+            }
+        }
+    }
+
+    fn get_new_setting(&self, setting: &Setting, block: &Block) -> Vec<NewSetting> {
+        let mut new_settings = Vec::<NewSetting>::new();
+        for entry in setting.get_entries() {
+            let new = NewSetting {
+                key: entry.get_key().to_string(),
+                value: entry.get_value().to_string(),
+                start_block_num: block.block_num,
+                end_block_num: MAX_BLOCK_NUM,
+            };
+            new_settings.push(new);
         }
+        new_settings
     }
     fn get_new_organization(&self, org: &organization::Organization, block: &Block) -> NewOrganization{
         NewOrganization {
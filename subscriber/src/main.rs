@@ -24,8 +24,9 @@ extern crate protobuf;
 extern crate regex;
 extern crate uuid;
 use bond_database::{
-    custom_types::*, connection_pool::ConnectionPool, models::*, tables_schema::*,
-    data_manager::{DataManager, TransactionType}
+    custom_types::*, models::*, tables_schema::*,
+    data_manager::TransactionType,
+    storage,
 };
 
 use log::LogLevel;
@@ -53,7 +54,9 @@ fn main() {
         (@arg dbuser: default_value("sawtooth") --dbuser +takes_value
             "the authorized user of the database")
         (@arg dbpass: default_value("sawtooth") --dbpass +takes_value
-            "the authorized user's password for database access"))
+            "the authorized user's password for database access")
+        (@subcommand rebuild =>
+            (about: "truncate the projection tables and replay the logged events")))
         .get_matches();
 
     let logger = match matches.occurrences_of("verbose") {
@@ -63,7 +66,7 @@ fn main() {
     };
 
     let dsn = format!(
-        "{}:{}@{}:{}/{}",
+        "postgres://{}:{}@{}:{}/{}",
         matches.value_of("dbuser").unwrap(),
         matches.value_of("dbpass").unwrap(),
         matches.value_of("dbhost").unwrap(),
@@ -71,12 +74,20 @@ fn main() {
         matches.value_of("dbname").unwrap()
     );
 
-    let conn = ConnectionPool::connect(&dsn).expect("Failed to connect to database");
+    // Select the backend from the DSN scheme: `postgres://` here in production,
+    // `sqlite://` for single-node deployments. The subscriber only ever talks to
+    // the store through the `Storage` trait.
+    let store = storage::open(&dsn).expect("Failed to connect to database");
     info!("Successfully connected to database");
-    let manager = DataManager::new(&conn).expect("Failed to connect to database");
-    let event_handler = EventHandler::new(manager);
+    let mut event_handler = EventHandler::new(store);
+
+    if matches.subcommand_matches("rebuild").is_some() {
+        event_handler.rebuild().expect("Failed to rebuild projection");
+        return;
+    }
+
     let mut subscriber = Subscriber::new("tcp://192.168.200.192:4010", event_handler);
-    subscriber.start(vec![String::from("0000000000000000")]).expect("error zmq ");
+    subscriber.start().expect("error zmq ");
     //subscriber.stop();
 
 }
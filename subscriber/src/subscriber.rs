@@ -12,92 +12,298 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use sawtooth_sdk::messages::events::{EventSubscription, EventFilter, EventFilter_FilterType, EventList};
-use sawtooth_sdk::messages::client_event::{ClientEventsSubscribeRequest, ClientEventsUnsubscribeRequest};
+use std::sync::mpsc::{channel, sync_channel, Sender, SyncSender};
+use std::thread;
+use std::time::Duration;
+
+use sawtooth_sdk::messages::events::{EventSubscription, EventFilter, EventFilter_FilterType};
+use sawtooth_sdk::messages::client_event::{
+    ClientEventsSubscribeRequest, ClientEventsSubscribeResponse,
+    ClientEventsSubscribeResponse_Status, ClientEventsUnsubscribeRequest,
+};
 use sawtooth_sdk::messages::validator::Message_MessageType;
 use sawtooth_sdk::messaging::zmq_stream::{ZmqMessageConnection, ZmqMessageSender};
 use sawtooth_sdk::messaging::stream::{MessageConnection, MessageSender, MessageReceiver};
-use bond_common::addressing;
 use protobuf;
+use tokio::runtime::Runtime;
+use tokio::timer::Delay;
+use futures::future::Future;
+use bond_common::addressing::{self, AddressFilter};
 use event_handler::EventHandler;
 use uuid::Uuid;
 
-pub struct Subscriber{
-    sender: ZmqMessageSender,
-    receiver: MessageReceiver,
-    event_handler: EventHandler,
+/// First back-off interval used after a dropped validator connection.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+/// Upper bound the exponential back-off is clamped to.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Bound on the number of undecoded-but-received blocks waiting for the DB
+/// worker. A full channel blocks the reader, exerting back-pressure on the ZMQ
+/// socket so a slow transaction cannot make us buffer unboundedly.
+const EVENT_CHANNEL_CAPACITY: usize = 64;
+
+/// A unit of work handed from the ZMQ reader to the DB worker. Jobs are drained
+/// in order, so blocks commit in the sequence they were received and a resume
+/// query only answers once every preceding block has been applied.
+enum Job {
+    /// Raw `EventList` bytes for one block, to be applied to the projection.
+    Events(Vec<u8>),
+    /// A request for the current resume points, answered once the worker has
+    /// drained every block queued ahead of it.
+    Resume(Sender<Vec<String>>),
+}
+
+/// A long-running, crash-safe delta subscriber.
+///
+/// `Subscriber` owns the validator endpoint rather than a single ZMQ socket so
+/// that it can transparently rebuild the connection whenever the stream drops.
+/// On every (re)subscribe it resumes from the highest block the projection has
+/// already committed, so the validator replays only the deltas we missed.
+pub struct Subscriber {
+    validator_address: String,
+    event_handler: Option<EventHandler>,
+    filters: Vec<AddressFilter>,
+    connection: Option<(ZmqMessageSender, MessageReceiver)>,
     is_active: bool,
 }
 
 impl Subscriber {
+    /// Subscribes to every delta under the bond namespace and the on-chain
+    /// settings namespace.
     pub fn new(validator_address: &str, event_handler: EventHandler) -> Subscriber {
-        let zmq = ZmqMessageConnection::new(validator_address);
-        let (mut sender, mut receiver) = zmq.create();
+        Subscriber::new_with_filters(validator_address, event_handler, Vec::new())
+    }
+
+    /// Subscribes only to the deltas selected by `filters`. An empty vector is
+    /// equivalent to `new` and follows the whole bond namespace and the
+    /// settings namespace.
+    pub fn new_with_filters(
+        validator_address: &str,
+        event_handler: EventHandler,
+        filters: Vec<AddressFilter>,
+    ) -> Subscriber {
         Subscriber {
-            sender,
-            receiver,
-            event_handler,
-            is_active: false
+            validator_address: validator_address.to_string(),
+            event_handler: Some(event_handler),
+            filters,
+            connection: None,
+            is_active: false,
         }
     }
 
-    pub fn start(&mut self, last_known_block_ids: Vec<String>) -> Result<(), String> {
-        let event_subscription_request = self.build_subscription_request(last_known_block_ids);
-        let content = protobuf::Message::write_to_bytes(&event_subscription_request).map_err(|err| err.to_string())?;
-        let correlation_id = Uuid::new_v4().to_string();
-        let mut response_future = self.sender.send(Message_MessageType::CLIENT_EVENTS_SUBSCRIBE_REQUEST, &correlation_id, &content).map_err(|err| err.to_string())?;
+    /// Runs the subscriber until `stop` is called. The `EventHandler` runs on a
+    /// dedicated DB worker thread draining a bounded channel, so a slow Postgres
+    /// transaction back-pressures the reader rather than stalling or dropping
+    /// ZMQ frames. The reader reconnects with exponential back-off whenever the
+    /// validator connection drops.
+    pub fn start(&mut self) -> Result<(), String> {
+        let event_handler = self.event_handler.take().ok_or_else(|| "subscriber already started".to_string())?;
+        let (job_tx, job_rx) = sync_channel::<Job>(EVENT_CHANNEL_CAPACITY);
+        let worker = thread::spawn(move || run_worker(event_handler, job_rx));
 
+        let result = self.run_reader(&job_tx);
+
+        // Dropping the sender closes the channel so the worker drains what is
+        // queued and then exits; join surfaces any panic and any apply error the
+        // worker stopped on.
+        drop(job_tx);
+        let worker_result = worker
+            .join()
+            .map_err(|_| "event worker thread panicked".to_string())?;
+        result.and(worker_result)
+    }
+
+    fn run_reader(&mut self, job_tx: &SyncSender<Job>) -> Result<(), String> {
+        let mut runtime = Runtime::new().map_err(|err| err.to_string())?;
         self.is_active = true;
+        let mut backoff = INITIAL_BACKOFF;
+
         while self.is_active {
+            match self.run_once(job_tx) {
+                Ok(()) => return Ok(()),
+                Err(err) => {
+                    // A stopped worker clears `is_active`; that is a fatal error,
+                    // not a dropped connection, so surface it instead of looping.
+                    if !self.is_active {
+                        return Err(err);
+                    }
+                    warn!("validator connection dropped: {}; reconnecting in {:?}", err, backoff);
+                    runtime
+                        .block_on(Delay::new(::std::time::Instant::now() + backoff))
+                        .map_err(|err| err.to_string())?;
+                    backoff = ::std::cmp::min(backoff * 2, MAX_BACKOFF);
+                }
+            }
+        }
+        Ok(())
+    }
 
-            let messaged_received = self.receiver.recv().map_err(|err| err.to_string())?;
-            let received = messaged_received.unwrap();
-            println!("test {:?}",received.get_message_type() );
-            self.event_handler.parse_events(received.get_content())?;
+    /// Establishes a single connection, (re)subscribes from the last committed
+    /// block and forwards the received, still undecoded frames to the worker
+    /// until the stream errors.
+    /// Returning `Ok` means the subscriber was asked to stop; returning `Err`
+    /// triggers a reconnect.
+    fn run_once(&mut self, job_tx: &SyncSender<Job>) -> Result<(), String> {
+        self.connect();
+        self.subscribe(job_tx)?;
 
+        while self.is_active {
+            let received = {
+                let receiver = &self.connection.as_ref().unwrap().1;
+                let message_received = receiver.recv().map_err(|err| err.to_string())?;
+                message_received.map_err(|err| err.to_string())?
+            };
+            // A full channel blocks here, back-pressuring the socket. A send
+            // error means the worker has stopped on a fatal apply error; stop the
+            // reader too rather than reconnecting into a dead pipeline.
+            if job_tx.send(Job::Events(received.get_content().to_vec())).is_err() {
+                self.is_active = false;
+                return Err("event worker stopped".to_string());
+            }
         }
         Ok(())
     }
 
+    fn connect(&mut self) {
+        let zmq = ZmqMessageConnection::new(&self.validator_address);
+        self.connection = Some(zmq.create());
+    }
+
+    /// Sends the subscribe request and inspects the response status. A fresh
+    /// validator replays from `last_known_block_ids`; if those blocks are no
+    /// longer on the chain the validator answers `UNKNOWN_BLOCK`, and we retry
+    /// once with an empty list so it streams from the current chain head.
+    fn subscribe(&mut self, job_tx: &SyncSender<Job>) -> Result<(), String> {
+        let last_known_block_ids = self.request_resume_blocks(job_tx)?;
+        if let ClientEventsSubscribeResponse_Status::UNKNOWN_BLOCK =
+            self.send_subscribe_request(last_known_block_ids)?
+        {
+            warn!("validator does not recognize our last known blocks; resubscribing from chain head");
+            match self.send_subscribe_request(Vec::new())? {
+                ClientEventsSubscribeResponse_Status::OK => Ok(()),
+                status => Err(format!("validator rejected subscription: {:?}", status)),
+            }
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Asks the worker for the resume points. The reply only arrives once the
+    /// worker has drained every queued block, so the ids reflect everything
+    /// committed so far.
+    fn request_resume_blocks(&mut self, job_tx: &SyncSender<Job>) -> Result<Vec<String>, String> {
+        let (reply_tx, reply_rx) = channel();
+        // Either failure means the worker has stopped on a fatal apply error.
+        let reply = job_tx
+            .send(Job::Resume(reply_tx))
+            .map_err(|err| err.to_string())
+            .and_then(|()| reply_rx.recv().map_err(|err| err.to_string()));
+        if reply.is_err() {
+            self.is_active = false;
+        }
+        reply
+    }
+
+    fn send_subscribe_request(
+        &mut self,
+        last_known_block_ids: Vec<String>,
+    ) -> Result<ClientEventsSubscribeResponse_Status, String> {
+        let request = self.build_subscription_request(last_known_block_ids);
+        let content = protobuf::Message::write_to_bytes(&request).map_err(|err| err.to_string())?;
+        let correlation_id = Uuid::new_v4().to_string();
+
+        let sender = &mut self.connection.as_mut().unwrap().0;
+        let mut response_future = sender
+            .send(Message_MessageType::CLIENT_EVENTS_SUBSCRIBE_REQUEST, &correlation_id, &content)
+            .map_err(|err| err.to_string())?;
+
+        let response_message = response_future.get().map_err(|err| err.to_string())?;
+        let response: ClientEventsSubscribeResponse =
+            protobuf::parse_from_bytes(response_message.get_content()).map_err(|err| err.to_string())?;
+        Ok(response.get_status())
+    }
+
     fn get_block_commit_subscription(&self) -> EventSubscription {
         let mut block_subscription = EventSubscription::new();
         block_subscription.set_event_type(String::from("sawtooth/block-commit"));
         block_subscription
     }
 
-    fn get_state_delta_subscription(&self) -> EventSubscription {
-        let mut state_delta_filter = EventFilter::new();
-        state_delta_filter.set_key(String::from("address"));
-        state_delta_filter.set_match_string(r"^000000".to_string());//addressing::FAMILY_NAMESPACE));
-        state_delta_filter.set_filter_type(EventFilter_FilterType::REGEX_ANY);
+    /// Builds one `EventSubscription` per address prefix. Sawtooth AND-combines
+    /// the `EventFilter`s within a single subscription, so packing several
+    /// prefixes into one subscription would match no address at all. Emitting a
+    /// separate subscription per prefix instead gives the intended "index any of
+    /// these object types" (OR) behavior across subscriptions.
+    fn get_state_delta_subscriptions(&self) -> Vec<EventSubscription> {
+        let match_prefixes: Vec<String> = if self.filters.is_empty() {
+            // Follow the whole bond family and the on-chain settings namespace,
+            // so governance parameters are indexed alongside the bond objects.
+            vec![addressing::get_bond_namespace(), addressing::get_settings_namespace()]
+        } else {
+            self.filters.iter().map(|filter| filter.match_prefix()).collect()
+        };
 
-        let mut state_delta_subscription = EventSubscription::new();
-        state_delta_subscription.set_event_type(String::from("sawtooth/state-delta"));
-        state_delta_subscription.set_filters(protobuf::RepeatedField::from_vec(vec![state_delta_filter]));
+        match_prefixes
+            .into_iter()
+            .map(|prefix| {
+                let mut state_delta_filter = EventFilter::new();
+                state_delta_filter.set_key(String::from("address"));
+                state_delta_filter.set_match_string(format!("^{}", prefix));
+                state_delta_filter.set_filter_type(EventFilter_FilterType::REGEX_ANY);
 
-        state_delta_subscription
+                let mut state_delta_subscription = EventSubscription::new();
+                state_delta_subscription.set_event_type(String::from("sawtooth/state-delta"));
+                state_delta_subscription
+                    .set_filters(protobuf::RepeatedField::from_vec(vec![state_delta_filter]));
+                state_delta_subscription
+            })
+            .collect()
     }
 
     fn build_subscription_request(&self, last_known_block_ids: Vec<String>) -> ClientEventsSubscribeRequest {
-        let block_subscription = self.get_block_commit_subscription();
-        let state_delta_subscription = self.get_state_delta_subscription();
+        let mut subscriptions = vec![self.get_block_commit_subscription()];
+        subscriptions.extend(self.get_state_delta_subscriptions());
 
         let mut event_subscription_request = ClientEventsSubscribeRequest::new();
-        event_subscription_request.set_subscriptions(protobuf::RepeatedField::from_vec(vec![block_subscription, state_delta_subscription]));
+        event_subscription_request.set_subscriptions(protobuf::RepeatedField::from_vec(subscriptions));
         event_subscription_request.set_last_known_block_ids(protobuf::RepeatedField::from_vec(last_known_block_ids));
 
         event_subscription_request
     }
 
-    pub fn stop(&mut self) -> Result<(), String>{
+    pub fn stop(&mut self) -> Result<(), String> {
         self.is_active = false;
-        let unsusbscribe_request = ClientEventsUnsubscribeRequest::new();
-        let content = protobuf::Message::write_to_bytes(&unsusbscribe_request).map_err(|err| err.to_string())?;
-        let correlation_id = Uuid::new_v4().to_string();;
-        let mut response_future = self.sender.send(Message_MessageType::CLIENT_EVENTS_UNSUBSCRIBE_REQUEST, &correlation_id, &content).map_err(|err| err.to_string())?;
-        self.sender.close();
+        if let Some((sender, _)) = self.connection.as_mut() {
+            let unsusbscribe_request = ClientEventsUnsubscribeRequest::new();
+            let content = protobuf::Message::write_to_bytes(&unsusbscribe_request).map_err(|err| err.to_string())?;
+            let correlation_id = Uuid::new_v4().to_string();
+            sender.send(Message_MessageType::CLIENT_EVENTS_UNSUBSCRIBE_REQUEST, &correlation_id, &content).map_err(|err| err.to_string())?;
+            sender.close();
+        }
         Ok(())
     }
+}
 
-
+/// Drains the job channel, applying each block to the projection in order. The
+/// loop exits when the reader drops its sender, or earlier if a block fails to
+/// apply: the projection is an ordered temporal log, so skipping a block would
+/// corrupt it and leave `last_block_id` stale, which would trigger a spurious
+/// reorg on the next block. A failed apply therefore stops the pipeline; on
+/// restart the subscriber resumes from the last committed block.
+fn run_worker(mut event_handler: EventHandler, job_rx: ::std::sync::mpsc::Receiver<Job>) -> Result<(), String> {
+    for job in job_rx {
+        match job {
+            Job::Events(content) => {
+                if let Err(err) = event_handler.parse_events(&content) {
+                    return Err(format!("failed to apply block events: {}", err));
+                }
+            }
+            Job::Resume(reply) => {
+                let resume_blocks = event_handler.get_last_known_blocks().unwrap_or_default();
+                // The reader may have gone away during a reconnect; ignore a
+                // closed reply channel.
+                let _ = reply.send(resume_blocks);
+            }
+        }
+    }
+    Ok(())
 }